@@ -1,9 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+use term;
 use term::color::YELLOW;
+use time;
 
 use core::{Package, PackageId, Resolve};
 use util::{Config, TaskPool, DependencyQueue, Fresh, Dirty, Freshness};
-use util::{CargoResult, Dependency, profile};
+use util::{CargoResult, Dependency, human, profile};
 
 use super::job::Job;
 
@@ -13,15 +17,46 @@ use super::job::Job;
 /// actual compilation step of each package. Packages enqueue units of work and
 /// then later on the entire graph is processed and compiled.
 pub struct JobQueue<'a, 'b> {
-    pool: TaskPool,
-    queue: DependencyQueue<(&'a PackageId, TargetStage),
+    queue: DependencyQueue<(&'a PackageId, TargetKind),
                            (&'a Package, Vec<(Job, Freshness)>)>,
     tx: Sender<Message>,
     rx: Receiver<Message>,
     resolve: &'a Resolve,
     active: uint,
-    pending: HashMap<(&'a PackageId, TargetStage), PendingBuild>,
+    pending: HashMap<(&'a PackageId, TargetKind), PendingBuild>,
     state: HashMap<&'a PackageId, Freshness>,
+    executor: Box<JobExecutor + Send>,
+    /// Wall-clock start time of each currently in-flight unit, keyed the same
+    /// way as `pending`. Only consulted when the progress bar is active.
+    starts: HashMap<(&'a PackageId, TargetKind), f64>,
+    /// Every `(package, target)` node that has been enqueued so far. Used to
+    /// compute critical-path weights without assuming a fixed set of stages.
+    enqueued: HashSet<(&'a PackageId, TargetKind)>,
+    /// Total number of units that have been enqueued so far, used as the
+    /// denominator of the progress bar.
+    units_total: uint,
+    /// Number of units that have finished, used as the numerator of the
+    /// progress bar.
+    units_done: uint,
+    /// Length of the longest remaining path from each node forward through
+    /// its dependents, computed once all enqueuing is done. Ready units with
+    /// a larger weight sit on a longer critical path and are dispatched
+    /// first.
+    weights: HashMap<(&'a PackageId, TargetKind), uint>,
+    /// If true, a failed unit doesn't abort the whole build; its dependents
+    /// are skipped but unrelated, independent work keeps going.
+    keep_going: bool,
+    /// Nodes that have failed, or that were skipped because one of their
+    /// dependencies failed. Checked before running a unit that has become
+    /// ready in keep-going mode.
+    poisoned: HashSet<(&'a PackageId, TargetKind)>,
+    /// Formatted error messages from every failed job, accumulated so they
+    /// can all be reported together at the end in keep-going mode.
+    failures: Vec<String>,
+    /// Packages we've already printed a "Compiling"/"Fresh" line for, so it
+    /// only happens once even though a package now has several independent
+    /// units in flight at once.
+    announced: HashSet<&'a PackageId>,
 }
 
 /// A helper structure for metadata about the state of a building package.
@@ -31,33 +66,171 @@ struct PendingBuild {
     /// Current freshness state of this package. Any dirty target within a
     /// package will cause the entire package to become dirty.
     fresh: Freshness,
+    /// Whether any job belonging to this unit has reported failure. This is
+    /// tracked independently of which particular message happens to zero
+    /// out `amt`, so a failure is never lost just because a later success
+    /// message is the one that finishes the unit.
+    failed: bool,
 }
 
-/// Current stage of compilation for an individual package.
-///
-/// This is the second layer of keys on the dependency queue to track the state
-/// of where a particular package is in the compilation pipeline. Each of these
-/// stages has a network of dependencies among them, outlined by the
-/// `Dependency` implementation found below.
+/// A single build unit within a package.
 ///
-/// Each build step for a package is registered with one of these stages, and
-/// each stage has a vector of work to perform in parallel.
+/// This is the second layer of keys on the dependency queue, and is what
+/// gives the queue its real parallelism: rather than every target in a
+/// package funneling through shared "all libraries", "all binaries" barrier
+/// nodes, each target tracks only the dependencies it actually has. A binary
+/// that doesn't use the package's build script output, for instance, isn't
+/// held back by one that does.
 #[deriving(Hash, PartialEq, Eq, Clone, PartialOrd, Ord, Show)]
-pub enum TargetStage {
-    StageStart,
-    StageCustomBuild,
-    StageLibraries,
-    StageBinaries,
-    StageEnd,
+pub enum TargetKind {
+    /// The package's build script, if it has one.
+    CustomBuild,
+    /// The package's library target. Downstream packages as well as this
+    /// package's own binaries/examples/tests link against this.
+    Lib,
+    /// A single binary, example, or test target, identified by name so that
+    /// independent targets of the same kind don't serialize on each other.
+    Other(String),
+}
+
+type Message = (PackageId, TargetKind, Freshness, CargoResult<()>);
+
+/// Abstracts over how an individual `Job` is actually carried out once it is
+/// dequeued from the dependency graph.
+///
+/// The queue itself only knows about freshness and dependency ordering; it
+/// hands a ready `Job` to a `JobExecutor` and waits for the result to show up
+/// on `tx`, which is always a clone of `JobQueue`'s own channel. This is what
+/// lets compilation be spread across remote build endpoints instead of only
+/// ever running on the local `TaskPool`.
+pub trait JobExecutor {
+    /// Begin executing `job` for `id`'s `kind` target. The result must
+    /// eventually be sent on `tx` as `(id, kind, fresh, result)`, same as the
+    /// local executor does, regardless of where the job actually ran.
+    fn execute(&mut self, id: PackageId, kind: TargetKind, fresh: Freshness,
+               job: Job, tx: Sender<Message>);
+}
+
+/// The default `JobExecutor`: runs every job on the local `TaskPool`.
+struct LocalExecutor {
+    pool: TaskPool,
+}
+
+impl LocalExecutor {
+    fn new(jobs: uint) -> LocalExecutor {
+        LocalExecutor { pool: TaskPool::new(jobs) }
+    }
+}
+
+impl JobExecutor for LocalExecutor {
+    fn execute(&mut self, id: PackageId, kind: TargetKind, fresh: Freshness,
+               job: Job, tx: Sender<Message>) {
+        self.pool.execute(proc() {
+            tx.send((id, kind, fresh, job.run(fresh)));
+        });
+    }
+}
+
+/// A single remote machine willing to build jobs on our behalf.
+///
+/// Endpoints are intentionally dumb: they know their own name, how many jobs
+/// they're willing to run concurrently, and how many they currently have in
+/// flight. Choosing which endpoint gets a job is handled by `RemoteExecutor`.
+pub struct RemoteEndpoint {
+    /// Human readable name identifying this endpoint.
+    pub name: String,
+    /// Maximum number of jobs this endpoint will run concurrently.
+    pub capacity: uint,
+    in_flight: Arc<Mutex<uint>>,
+}
+
+impl RemoteEndpoint {
+    pub fn new(name: String, capacity: uint) -> RemoteEndpoint {
+        RemoteEndpoint {
+            name: name,
+            capacity: capacity,
+            in_flight: Arc::new(Mutex::new(0u)),
+        }
+    }
+
+    fn load(&self) -> uint {
+        *self.in_flight.lock()
+    }
+
+    fn is_free(&self) -> bool {
+        self.load() < self.capacity
+    }
+
+    /// Ships `job` off to this endpoint and reports the result back on `tx`
+    /// once it completes, freeing up a capacity slot in the process.
+    ///
+    /// This stands in for the real remote build protocol (connect, upload
+    /// inputs, stream back the result); the scheduling logic above doesn't
+    /// need to know the difference.
+    fn dispatch(&self, id: PackageId, kind: TargetKind, fresh: Freshness,
+                job: Job, tx: Sender<Message>) {
+        *self.in_flight.lock() += 1;
+        let in_flight = self.in_flight.clone();
+        spawn(proc() {
+            let result = job.run(fresh);
+            *in_flight.lock() -= 1;
+            tx.send((id, kind, fresh, result));
+        });
+    }
+}
+
+/// Dispatches jobs across a set of remote build endpoints, like butido's
+/// `EndpointScheduler` spreads work across its workers.
+///
+/// Each ready job goes to whichever configured endpoint with a free slot
+/// currently has the fewest jobs in flight. If every endpoint is saturated,
+/// the job falls back
+/// to running on the local `TaskPool` rather than queueing up and stalling
+/// the build.
+pub struct RemoteExecutor {
+    endpoints: Vec<RemoteEndpoint>,
+    local: LocalExecutor,
 }
 
-type Message = (PackageId, TargetStage, Freshness, CargoResult<()>);
+impl RemoteExecutor {
+    pub fn new(endpoints: Vec<RemoteEndpoint>, jobs: uint) -> RemoteExecutor {
+        RemoteExecutor {
+            endpoints: endpoints,
+            local: LocalExecutor::new(jobs),
+        }
+    }
+
+    /// Finds the endpoint with spare capacity that currently has the least
+    /// work in flight, if any are free.
+    fn least_loaded(&mut self) -> Option<&mut RemoteEndpoint> {
+        let mut best = None;
+        let mut best_load = 0u;
+        for endpoint in self.endpoints.iter_mut() {
+            if !endpoint.is_free() { continue }
+            let load = endpoint.load();
+            if best.is_none() || load < best_load {
+                best_load = load;
+                best = Some(endpoint);
+            }
+        }
+        best
+    }
+}
+
+impl JobExecutor for RemoteExecutor {
+    fn execute(&mut self, id: PackageId, kind: TargetKind, fresh: Freshness,
+               job: Job, tx: Sender<Message>) {
+        match self.least_loaded() {
+            Some(endpoint) => endpoint.dispatch(id, kind, fresh, job, tx),
+            None => self.local.execute(id, kind, fresh, job, tx),
+        }
+    }
+}
 
 impl<'a, 'b> JobQueue<'a, 'b> {
     pub fn new(resolve: &'a Resolve, config: &mut Config) -> JobQueue<'a, 'b> {
         let (tx, rx) = channel();
         JobQueue {
-            pool: TaskPool::new(config.jobs()),
             queue: DependencyQueue::new(),
             tx: tx,
             rx: rx,
@@ -65,10 +238,37 @@ impl<'a, 'b> JobQueue<'a, 'b> {
             active: 0,
             pending: HashMap::new(),
             state: HashMap::new(),
+            executor: box LocalExecutor::new(config.jobs()) as Box<JobExecutor + Send>,
+            starts: HashMap::new(),
+            enqueued: HashSet::new(),
+            units_total: 0,
+            units_done: 0,
+            weights: HashMap::new(),
+            keep_going: false,
+            poisoned: HashSet::new(),
+            failures: Vec::new(),
+            announced: HashSet::new(),
         }
     }
 
-    pub fn enqueue(&mut self, pkg: &'a Package, stage: TargetStage,
+    /// Switches this queue over to dispatching work to `endpoints` instead of
+    /// only building locally, falling back to the local `TaskPool` (sized by
+    /// `config.jobs()`) whenever every endpoint is busy.
+    pub fn use_remote_endpoints(&mut self, endpoints: Vec<RemoteEndpoint>,
+                                config: &mut Config) {
+        self.executor = box RemoteExecutor::new(endpoints, config.jobs())
+                            as Box<JobExecutor + Send>;
+    }
+
+    /// Enables `--keep-going` mode: a failed unit no longer aborts the whole
+    /// build. Its transitive dependents are skipped, but independent work
+    /// elsewhere in the graph keeps running, and all failures are reported
+    /// together once the queue drains.
+    pub fn keep_going(&mut self, keep_going: bool) {
+        self.keep_going = keep_going;
+    }
+
+    pub fn enqueue(&mut self, pkg: &'a Package, kind: TargetKind,
                    jobs: Vec<(Job, Freshness)>) {
         // Record the freshness state of this package as dirty if any job is
         // dirty or fresh otherwise
@@ -76,9 +276,12 @@ impl<'a, 'b> JobQueue<'a, 'b> {
         let prev = self.state.find_or_insert(pkg.get_package_id(), fresh);
         *prev = prev.combine(fresh);
 
+        self.units_total += 1;
+        self.enqueued.insert((pkg.get_package_id(), kind.clone()));
+
         // Add the package to the dependency graph
         self.queue.enqueue(&self.resolve, Fresh,
-                           (pkg.get_package_id(), stage),
+                           (pkg.get_package_id(), kind),
                            (pkg, jobs));
     }
 
@@ -90,63 +293,180 @@ impl<'a, 'b> JobQueue<'a, 'b> {
     pub fn execute(&mut self, config: &mut Config) -> CargoResult<()> {
         let _p = profile::start("executing the job graph");
 
+        let mut progress = if show_progress(config) {
+            Some(ProgressState::new())
+        } else {
+            None
+        };
+
+        // The weights are static for the whole run, so they only need to be
+        // computed once, after every package has been enqueued.
+        if self.weights.is_empty() {
+            self.compute_weights();
+        }
+
         // Iteratively execute the dependency graph. Each turn of this loop will
         // schedule as much work as possible and then wait for one job to finish,
         // possibly scheduling more work afterwards.
         while self.queue.len() > 0 {
+            // Collect everything that's ready to run this turn rather than
+            // dispatching in whatever order `dequeue()` happens to yield, so
+            // that units on a longer critical path can jump the queue.
+            let mut ready = Vec::new();
             loop {
                 match self.queue.dequeue() {
-                    Some((fresh, (_, stage), (pkg, jobs))) => {
-                        try!(self.run(pkg, stage, fresh, jobs, config));
+                    Some((fresh, key, payload)) => {
+                        // In keep-going mode a node can become ready because
+                        // one of its dependencies was poisoned rather than
+                        // actually finished. Don't build on top of a failure;
+                        // mark it poisoned too and let the queue carry on so
+                        // its own dependents get the same treatment.
+                        if self.keep_going && self.depends_on_poisoned(&key) {
+                            self.poisoned.insert(key.clone());
+                            self.units_done += 1;
+                            self.starts.remove(&key);
+                            self.pending.remove(&key);
+                            self.queue.finish(&key, Dirty);
+                        } else {
+                            ready.push((fresh, key, payload));
+                        }
                     }
                     None => break,
                 }
             }
+            ready.sort_by(|a, b| {
+                let &(_, ref a_key, _) = a;
+                let &(_, ref b_key, _) = b;
+                self.weight_of(a_key).cmp(&self.weight_of(b_key)).reverse()
+            });
+            for (fresh, (_, kind), (pkg, jobs)) in ready.move_iter() {
+                try!(self.run(pkg, kind, fresh, jobs, config, &mut progress));
+            }
+
+            if let Some(ref mut progress) = progress {
+                progress.draw(&self.pending, &self.starts,
+                               self.units_done, self.units_total);
+            }
+
+            // In keep-going mode the dequeue loop above may have poisoned
+            // and finished every unit that was ready this turn without ever
+            // spawning a job, e.g. when a whole branch of the graph depends
+            // on an earlier failure. If nothing is outstanding there is
+            // nothing coming on `self.rx`, so go try the queue again instead
+            // of blocking on a message that will never arrive.
+            if self.active == 0 {
+                continue;
+            }
 
             // Now that all possible work has been scheduled, wait for a piece
             // of work to finish. If any package fails to build then we stop
             // scheduling work as quickly as possibly.
-            let (id, stage, fresh, result) = self.rx.recv();
+            // In keep-going mode only the failed unit's dependents are held
+            // back; unrelated units keep being scheduled.
+            let (id, kind, fresh, result) = self.rx.recv();
             let id = *self.state.keys().find(|&k| *k == &id).unwrap();
             self.active -= 1;
+            let is_err = result.is_err();
+            let done = {
+                let state = self.pending.get_mut(&(id, kind.clone()));
+                state.amt -= 1;
+                if is_err {
+                    state.failed = true;
+                } else {
+                    state.fresh = state.fresh.combine(fresh);
+                }
+                state.amt == 0
+            };
+
             match result {
                 Ok(()) => {
-                    let state = self.pending.get_mut(&(id, stage));
-                    state.amt -= 1;
-                    state.fresh = state.fresh.combine(fresh);
-                    if state.amt == 0 {
-                        self.queue.finish(&(id, stage), state.fresh);
+                    // A unit is only really finished once every one of its
+                    // jobs has reported in, and it only really succeeded if
+                    // none of them failed along the way -- so a trailing
+                    // success message must not finish the unit as "clean"
+                    // out from under an earlier failure.
+                    if done {
+                        let state = self.pending.remove(&(id, kind.clone())).unwrap();
+                        self.starts.remove(&(id, kind.clone()));
+                        self.units_done += 1;
+                        if state.failed {
+                            self.poisoned.insert((id, kind.clone()));
+                            self.queue.finish(&(id, kind), Dirty);
+                        } else {
+                            self.queue.finish(&(id, kind), state.fresh);
+                        }
                     }
                 }
                 Err(e) => {
-                    if self.active > 0 {
-                        try!(config.shell().say(
-                                    "Build failed, waiting for other \
-                                     jobs to finish...", YELLOW));
-                        for _ in self.rx.iter().take(self.active) {}
+                    if self.keep_going {
+                        // Record the failure, poison this node so its
+                        // dependents are skipped instead of built, but keep
+                        // draining the queue so unrelated, independent work
+                        // still completes.
+                        self.failures.push(e.to_string());
+                        if done {
+                            self.poisoned.insert((id, kind.clone()));
+                            self.starts.remove(&(id, kind.clone()));
+                            self.pending.remove(&(id, kind.clone()));
+                            self.units_done += 1;
+                            self.queue.finish(&(id, kind), Dirty);
+                        }
+                    } else {
+                        if let Some(ref mut progress) = progress {
+                            progress.clear();
+                        }
+                        if self.active > 0 {
+                            try!(config.shell().say(
+                                        "Build failed, waiting for other \
+                                         jobs to finish...", YELLOW));
+                            for _ in self.rx.iter().take(self.active) {}
+                        }
+                        return Err(e)
                     }
-                    return Err(e)
                 }
             }
         }
 
+        if !self.failures.is_empty() {
+            if let Some(ref mut progress) = progress {
+                progress.clear();
+            }
+            return Err(human(format!("{} job(s) failed:\n{}",
+                                      self.failures.len(),
+                                      self.failures.connect("\n"))))
+        }
+
+        if let Some(ref mut progress) = progress {
+            progress.clear();
+        }
+
         log!(5, "rustc jobs completed");
 
         Ok(())
     }
 
-    /// Execute a stage of compilation for a package.
+    /// Execute one target's jobs for a package.
     ///
     /// The input freshness is from `dequeue()` and indicates the combined
     /// freshness of all upstream dependencies. This function will schedule all
     /// work in `jobs` to be executed.
-    fn run(&mut self, pkg: &'a Package, stage: TargetStage, fresh: Freshness,
-           jobs: Vec<(Job, Freshness)>, config: &mut Config) -> CargoResult<()> {
+    fn run(&mut self, pkg: &'a Package, kind: TargetKind, fresh: Freshness,
+           jobs: Vec<(Job, Freshness)>, config: &mut Config,
+           progress: &mut Option<ProgressState>) -> CargoResult<()> {
         let njobs = jobs.len();
         let amt = if njobs == 0 {1} else {njobs};
         let id = pkg.get_package_id().clone();
 
-        if stage == StageStart {
+        // Announce a package the first time any of its targets actually
+        // starts running, rather than tying the message to one particular
+        // stage that may no longer run first (or at all) for this package.
+        if self.announced.insert(pkg.get_package_id()) {
+            // The progress display erases and redraws in place, so any other
+            // status line written to stderr while it's up must clear it
+            // first or the next redraw's erase math will be off.
+            if let Some(ref mut progress) = *progress {
+                progress.clear();
+            }
             match fresh.combine(self.state[pkg.get_package_id()]) {
                 Fresh => try!(config.shell().verbose(|c| {
                     c.status("Fresh", pkg)
@@ -157,12 +477,14 @@ impl<'a, 'b> JobQueue<'a, 'b> {
 
         // While the jobs are all running, we maintain some metadata about how
         // many are running, the current state of freshness (of all the combined
-        // jobs), and the stage to pass to finish() later on.
+        // jobs), and the kind to pass to finish() later on.
         self.active += amt;
-        self.pending.insert((pkg.get_package_id(), stage), PendingBuild {
+        self.pending.insert((pkg.get_package_id(), kind.clone()), PendingBuild {
             amt: amt,
             fresh: fresh,
+            failed: false,
         });
+        self.starts.insert((pkg.get_package_id(), kind.clone()), time::precise_time_s());
 
         for (job, job_freshness) in jobs.move_iter() {
             let fresh = job_freshness.combine(fresh);
@@ -171,43 +493,185 @@ impl<'a, 'b> JobQueue<'a, 'b> {
             if fresh == Dirty {
                 try!(config.shell().verbose(|shell| job.describe(shell)));
             }
-            self.pool.execute(proc() {
-                my_tx.send((id, stage, fresh, job.run(fresh)));
-            });
+            self.executor.execute(id, kind.clone(), fresh, job, my_tx);
         }
 
         // If no work was scheduled, make sure that a message is actually send
         // on this channel.
         if njobs == 0 {
-            self.tx.send((id, stage, fresh, Ok(())));
+            self.tx.send((id, kind, fresh, Ok(())));
         }
         Ok(())
     }
+
+    fn weight_of(&self, node: &(&'a PackageId, TargetKind)) -> uint {
+        *self.weights.find(node).unwrap_or(&0)
+    }
+
+    /// True if any of `node`'s direct dependencies failed or were themselves
+    /// skipped as unbuildable. The `DependencyQueue` only ever hands back
+    /// units whose dependencies have all finished (one way or another), so
+    /// checking one level up is enough; poisoning is discovered and
+    /// propagated outward one generation at a time as each successive layer
+    /// of dependents becomes ready.
+    fn depends_on_poisoned(&self, node: &(&'a PackageId, TargetKind)) -> bool {
+        node.dependencies(&self.resolve).iter().any(|dep| {
+            self.poisoned.contains(dep)
+        })
+    }
+
+    /// Computes, for every unit enqueued so far, the length of the longest
+    /// remaining path from that node forward through whatever ends up
+    /// depending on it.
+    ///
+    /// This walks the same `Dependency::dependencies` edges used to drive
+    /// the queue, but inverted: instead of "what does this node need first",
+    /// it asks "what ends up depending on this node", and takes the deepest
+    /// such chain. Nodes with a high weight gate a lot of downstream work, so
+    /// starting them as early as possible keeps them from becoming the tail
+    /// of the build.
+    fn compute_weights(&mut self) {
+        let nodes: Vec<(&'a PackageId, TargetKind)> =
+            self.enqueued.iter().map(|n| n.clone()).collect();
+
+        let mut dependents: HashMap<(&'a PackageId, TargetKind),
+                                     Vec<(&'a PackageId, TargetKind)>> = HashMap::new();
+        for node in nodes.iter() {
+            for dep in node.dependencies(&self.resolve).move_iter() {
+                dependents.find_or_insert(dep, Vec::new()).push(node.clone());
+            }
+        }
+
+        fn weight<'a>(node: &(&'a PackageId, TargetKind),
+                      dependents: &HashMap<(&'a PackageId, TargetKind),
+                                           Vec<(&'a PackageId, TargetKind)>>,
+                      cache: &mut HashMap<(&'a PackageId, TargetKind), uint>)
+                      -> uint {
+            if let Some(w) = cache.find(node) {
+                return *w;
+            }
+            let downstream = match dependents.find(node) {
+                Some(next) => {
+                    next.iter().map(|n| weight(n, dependents, cache)).max().unwrap_or(0)
+                }
+                None => 0,
+            };
+            let w = 1 + downstream;
+            cache.insert(node.clone(), w);
+            w
+        }
+
+        let mut cache = HashMap::new();
+        for node in nodes.iter() {
+            let w = weight(node, &dependents, &mut cache);
+            self.weights.insert(node.clone(), w);
+        }
+    }
+}
+
+/// Whether the recv loop in `execute()` should maintain a live progress
+/// display. Only worth doing when stderr is actually a terminal someone is
+/// watching, and only when more than one job can be in flight at once.
+fn show_progress(config: &mut Config) -> bool {
+    config.jobs() > 1 && stderr_is_tty()
 }
 
-impl<'a> Dependency<&'a Resolve> for (&'a PackageId, TargetStage) {
+fn stderr_is_tty() -> bool {
+    term::TerminfoTerminal::new(io::stderr()).is_some()
+}
+
+/// Draws one live line per in-flight unit plus a "done/total units" summary,
+/// redrawing in place as messages arrive on the job queue's channel.
+///
+/// Modeled on indicatif-style multi-progress rendering: each call to `draw`
+/// erases the previous frame and repaints the current one, so the terminal
+/// always shows a single block of up-to-date lines rather than a scrolling
+/// log.
+struct ProgressState {
+    last_lines: uint,
+}
+
+impl ProgressState {
+    fn new() -> ProgressState {
+        ProgressState { last_lines: 0 }
+    }
+
+    fn draw<'a>(&mut self,
+                pending: &HashMap<(&'a PackageId, TargetKind), PendingBuild>,
+                starts: &HashMap<(&'a PackageId, TargetKind), f64>,
+                done: uint, total: uint) {
+        self.clear();
+
+        let mut stderr = io::stderr();
+        let now = time::precise_time_s();
+        let mut lines = 0u;
+        for (key, _) in pending.iter() {
+            let &(id, ref kind) = key;
+            let elapsed = starts.find(key).map(|s| now - *s).unwrap_or(0.0);
+            let _ = writeln!(stderr, "{:>12} {} ({}) {:.1}s",
+                              "Building", id, kind, elapsed);
+            lines += 1;
+        }
+        let _ = writeln!(stderr, "{:>12} {}/{} units", "Progress", done, total);
+        lines += 1;
+
+        self.last_lines = lines;
+    }
+
+    /// Erases whatever this renderer last drew, restoring normal shell
+    /// output. Safe to call multiple times, including when nothing has been
+    /// drawn yet.
+    ///
+    /// This writes to stderr, matching `draw()`, so that erasing the
+    /// progress display and anything else writing status lines to stderr
+    /// (e.g. the "Compiling"/"Fresh" lines in `run()`) stay on the same
+    /// stream and the cursor math here keeps matching reality.
+    fn clear(&mut self) {
+        let mut stderr = io::stderr();
+        for _ in range(0, self.last_lines) {
+            let _ = write!(stderr, "\x1b[1A\x1b[2K");
+        }
+        self.last_lines = 0;
+    }
+}
+
+impl<'a> Dependency<&'a Resolve> for (&'a PackageId, TargetKind) {
     fn dependencies(&self, resolve: &&'a Resolve)
-                    -> Vec<(&'a PackageId, TargetStage)> {
+                    -> Vec<(&'a PackageId, TargetKind)> {
         // This implementation of `Dependency` is the driver for the structure
-        // of the dependency graph of packages to be built. The "key" here is
-        // a pair of the package being built and the stage that it's at.
+        // of the dependency graph of units to be built. The "key" here is a
+        // pair of the package being built and the specific target within it.
         //
-        // Each stage here lists dependencies on the previous stages except for
-        // the start state which depends on the ending state of all dependent
-        // packages (as determined by the resolve context).
-        let (id, stage) = *self;
-        match stage {
-            StageStart => {
+        // Unlike the old fixed-stage pipeline, a unit only depends on the
+        // exact targets it actually needs: a binary depends on its own
+        // package's library and build script, not on every other binary or
+        // example in the package, and a package's library depends on the
+        // libraries of its own dependencies rather than waiting for their
+        // binaries too.
+        let (id, ref kind) = *self;
+        match *kind {
+            // A build script can itself need another package's library to
+            // be built first, e.g. a `links` build-dependency that it
+            // compiles against. Depend on the library of each dependency
+            // just like `Lib` below does, so cross-package build-script
+            // ordering isn't silently dropped.
+            CustomBuild => {
                 resolve.deps(id).move_iter().flat_map(|a| a).filter(|dep| {
                     *dep != id
                 }).map(|dep| {
-                    (dep, StageEnd)
+                    (dep, Lib)
                 }).collect()
             }
-            StageCustomBuild => vec![(id, StageStart)],
-            StageLibraries => vec![(id, StageCustomBuild)],
-            StageBinaries => vec![(id, StageLibraries)],
-            StageEnd => vec![(id, StageBinaries), (id, StageLibraries)],
+            Lib => {
+                let mut deps = vec![(id, CustomBuild)];
+                deps.extend(resolve.deps(id).move_iter().flat_map(|a| a).filter(|dep| {
+                    *dep != id
+                }).map(|dep| {
+                    (dep, Lib)
+                }));
+                deps
+            }
+            Other(_) => vec![(id, Lib), (id, CustomBuild)],
         }
     }
 }